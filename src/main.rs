@@ -1,17 +1,33 @@
 #![allow(dead_code)]
 
 use std::{
-    fs::{self, File},
-    io::{BufRead, BufReader, BufWriter, Read, Write, IoSlice},
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
-    time::Instant,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use reqwest::{
     header::{self, HeaderMap, HeaderValue},
-    Client, IntoUrl, StatusCode, Url,
+    Client, StatusCode, Url,
 };
 
+use rand::Rng;
+
+use cab::Cabinet;
+
+use flate2::read::GzDecoder;
+
+use digest::Digest;
+use md5::Md5;
+use sha2::Sha256;
+
 use indicatif::{ProgressBar, ProgressStyle};
 
 use rayon::{prelude::*, ThreadPoolBuilder};
@@ -28,15 +44,129 @@ static DEFAULT_UA: &str =
 
 static BUFFER_SIZE: usize = 1024 * 1024;
 
+// A counting semaphore used to cap the number of in-flight requests to a single host,
+// independent of the global rayon thread count.
+struct Semaphore {
+    available: Mutex<usize>,
+    cond: Condvar,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Semaphore { available: Mutex::new(permits),
+                    cond: Condvar::new() }
+    }
+
+    fn acquire(&self) -> SemaphorePermit<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.cond.wait(available).unwrap();
+        }
+        *available -= 1;
+        SemaphorePermit { sem: self }
+    }
+}
+
+struct SemaphorePermit<'a> {
+    sem: &'a Semaphore,
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        let mut available = self.sem.available.lock().unwrap();
+        *available += 1;
+        self.sem.cond.notify_one();
+    }
+}
+
+lazy_static! {
+    static ref HOST_SEMAPHORES: Mutex<HashMap<String, Arc<Semaphore>>> = Mutex::new(HashMap::new());
+}
+
+// Returns the shared semaphore for `host`, creating it with `max_per_host` permits the first
+// time the host is seen.
+fn host_semaphore(host: &str, max_per_host: usize) -> Arc<Semaphore> {
+    HOST_SEMAPHORES.lock()
+                    .unwrap()
+                    .entry(host.to_owned())
+                    .or_insert_with(|| Arc::new(Semaphore::new(max_per_host)))
+                    .clone()
+}
+
+// Outcome of one attempt inside `with_retry`.
+enum Retry<T> {
+    Ok(T),
+    Retryable(Option<Duration>),
+    Fatal,
+}
+
+// Calls `attempt` until it reports `Ok`, `Fatal`, or `retries` retries are exhausted.
+fn with_retry<T>(retries: usize, base_delay: Duration, mut attempt: impl FnMut() -> Retry<T>) -> Option<T> {
+    let mut tried = 0;
+    loop {
+        match attempt() {
+            Retry::Ok(v) => return Some(v),
+            Retry::Fatal => return None,
+            Retry::Retryable(_) if tried == retries => return None,
+            Retry::Retryable(retry_after) => {
+                thread::sleep(retry_after.unwrap_or_else(|| jittered_backoff(base_delay, tried)));
+                tried += 1;
+            }
+        }
+    }
+}
+
+fn jittered_backoff(base_delay: Duration, attempt: usize) -> Duration {
+    let backoff = base_delay * 2u32.pow(attempt.min(10) as u32);
+    let jitter_ms = rand::thread_rng().gen_range(0..=(backoff.as_millis() as u64 / 2).max(1));
+    backoff + Duration::from_millis(jitter_ms)
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status,
+             StatusCode::TOO_MANY_REQUESTS
+             | StatusCode::INTERNAL_SERVER_ERROR
+             | StatusCode::BAD_GATEWAY
+             | StatusCode::SERVICE_UNAVAILABLE
+             | StatusCode::GATEWAY_TIMEOUT)
+}
+
+// Parses a `Retry-After` header given in seconds; the HTTP-date form isn't handled.
+fn retry_after_delay(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?;
+    value.parse::<u64>().ok().map(Duration::from_secs)
+}
+
+// Extracts a value from response headers that identifies this exact representation of the
+// resource, preferring the (usually stronger) `ETag` and falling back to `Last-Modified`.
+// Tagged with which header it came from, so a value from one never compares equal to a value
+// from the other.
+fn validator_of(headers: &HeaderMap) -> Option<String> {
+    if let Some(etag) = headers.get(header::ETAG).and_then(|v| v.to_str().ok()) {
+        return Some(format!("etag:{}", etag));
+    }
+    headers.get(header::LAST_MODIFIED)
+           .and_then(|v| v.to_str().ok())
+           .map(|lm| format!("lastmod:{}", lm))
+}
+
 pub struct RemoteFile {
     pub url: Url,
     pub name: PathBuf,
     pub length: usize,
+    pub validator: Option<String>,
     client: Client,
+    host_semaphore: Option<Arc<Semaphore>>,
+    retries: usize,
+    retry_delay: Duration,
 }
 
 impl RemoteFile {
-    fn from(url: &str) -> Option<Self> {
+    fn from(url: &str,
+            max_per_host: Option<usize>,
+            retries: usize,
+            retry_delay: Duration)
+            -> Option<Self> {
         let url = Url::parse(url).ok()?;
         let client = {
             let mut headers = HeaderMap::new();
@@ -47,12 +177,27 @@ impl RemoteFile {
                              .ok()?
         };
         // let client = Client::new();
-        let resp = client.head(url)
-                         //  .header(header::USER_AGENT, DEFAULT_UA)
-                         .send()
-                         .ok()?;
+        // Gates the HEAD too, not just the later GETs, keyed to the pre-redirect host since
+        // that's what's actually dialed first.
+        let head_semaphore = max_per_host.and_then(|n| url.host_str().map(|h| host_semaphore(h, n)));
+        let resp = {
+            let _permit = head_semaphore.as_deref().map(Semaphore::acquire);
+            with_retry(retries, retry_delay, || match client.head(url.clone()).send() {
+                Ok(resp) if resp.status().is_success() => Retry::Ok(resp),
+                Ok(resp) if is_retryable_status(resp.status()) => {
+                    Retry::Retryable(retry_after_delay(resp.headers()))
+                }
+                Ok(_) => Retry::Fatal,
+                Err(_) => Retry::Retryable(None),
+            })?
+        };
         let url = resp.url().to_owned();
         let length = resp.content_length()? as usize;
+        let validator = validator_of(resp.headers());
+        // Keyed to `url` *after* the rebind above: the HEAD commonly redirects (e.g. Microsoft's
+        // default --server redirects to blob-storage hosts), and this is the host the later
+        // ranged/sequential GETs actually hit.
+        let host_semaphore = max_per_host.and_then(|n| url.host_str().map(|h| host_semaphore(h, n)));
         let mut name = None;
         if resp.status().is_success() {
             if let Some(ctd) = resp.headers().get(header::CONTENT_DISPOSITION) {
@@ -76,80 +221,413 @@ impl RemoteFile {
             Some(RemoteFile { url,
                               name,
                               length,
-                              client })
+                              validator,
+                              client,
+                              host_semaphore,
+                              retries,
+                              retry_delay })
         } else {
             None
         }
     }
 
-    fn rdownload(&self, w: &mut impl Write) -> Option<&Path> {
-        fn get_ranged_data(client: &Client,
-                           url: impl IntoUrl,
-                           range: (usize, usize))
-                           -> Option<Box<[u8]>> {
+    // Writes each range directly to its offset in `file` instead of buffering in memory.
+    fn rdownload(&self, file: &File, resume_from: usize) -> Option<&Path> {
+        fn fetch_range_to_file(client: &Client,
+                               url: Url,
+                               file: &File,
+                               range: (usize, usize),
+                               host_semaphore: Option<&Semaphore>,
+                               written: &AtomicUsize,
+                               retries: usize,
+                               retry_delay: Duration)
+                               -> Option<()> {
+            let _permit = host_semaphore.map(Semaphore::acquire);
             let range_content = format!("bytes={}-{}", range.0, range.1 - 1);
-            let resp = &mut client.get(url)
-                                  //   .header(header::USER_AGENT, DEFAULT_UA)
-                                  .header(header::RANGE, range_content.as_str())
-                                  .send()
-                                  .ok()?;
-            if resp.status() == StatusCode::PARTIAL_CONTENT {
-                let mut buffer: Vec<_> = Vec::with_capacity(2 * BUFFER_SIZE);
-                resp.copy_to(&mut buffer).ok()?;
-                Some(buffer.into_boxed_slice())
-            } else {
-                None
+            let mut resp = with_retry(retries, retry_delay, || {
+                              match client.get(url.clone())
+                                          .header(header::RANGE, range_content.as_str())
+                                          .send() {
+                                  Ok(r) if r.status() == StatusCode::PARTIAL_CONTENT => Retry::Ok(r),
+                                  Ok(r) if is_retryable_status(r.status()) => {
+                                      Retry::Retryable(retry_after_delay(r.headers()))
+                                  }
+                                  Ok(_) => Retry::Fatal,
+                                  Err(_) => Retry::Retryable(None),
+                              }
+                          })?;
+
+            let buffer = &mut vec![0u8; BUFFER_SIZE];
+            let mut offset = range.0;
+            loop {
+                let count = resp.read(buffer).ok()?;
+                if count == 0 {
+                    break
+                }
+                write_at(file, &buffer[0..count], offset as u64).ok()?;
+                offset += count;
+                written.fetch_add(count, Ordering::Relaxed);
             }
+            Some(())
         }
 
-        // concurrency
-        let data: Option<Vec<_>> = {
-            let ranges = {
-                let mut ranges: Vec<_> = (0..(self.length / BUFFER_SIZE)).map(|i| {
-                                                                             (i * BUFFER_SIZE,
-                                                                              (i + 1) * BUFFER_SIZE)
-                                                                         })
-                                                                         .collect();
-                ranges.push((BUFFER_SIZE * (self.length / BUFFER_SIZE), self.length));
-                ranges
-            };
+        let ranges = {
+            let mut ranges: Vec<_> = ((resume_from / BUFFER_SIZE)..(self.length / BUFFER_SIZE))
+                .map(|i| (i * BUFFER_SIZE, (i + 1) * BUFFER_SIZE))
+                .collect();
+            ranges.push((BUFFER_SIZE * (self.length / BUFFER_SIZE), self.length));
+            if let Some(first) = ranges.first_mut() {
+                first.0 = first.0.max(resume_from);
+            }
+            ranges
+        };
 
+        let host_semaphore = self.host_semaphore.as_deref();
+        let written = AtomicUsize::new(resume_from);
+        let completed: Option<Vec<()>> =
             ranges.par_iter()
-                  .map(|(from, to)| get_ranged_data(&self.client, self.url.clone(), (*from, *to)))
-                  .collect()
-        };
+                  .map(|range| {
+                      fetch_range_to_file(&self.client,
+                                          self.url.clone(),
+                                          file,
+                                          *range,
+                                          host_semaphore,
+                                          &written,
+                                          self.retries,
+                                          self.retry_delay)
+                  })
+                  .collect();
+        completed?;
+
+        let saved_length = written.load(Ordering::Relaxed);
+        assert_eq!(self.length, saved_length);
 
-        if let Some(buffers) = data {
-            let buffer: &Vec<_> = &buffers.iter().map(|b| IoSlice::new(&*b)).collect();
-            let saved_length = w.write_vectored(buffer).ok()?;
-            assert_eq!(self.length, saved_length);
+        Some(self.name.as_path())
+    }
 
-            Some(self.name.as_path())
+    // `resume_from` is the number of bytes already present in `w`; when it is non-zero the
+    // request is issued as `Range: bytes=<resume_from>-` and the new bytes are appended rather
+    // than the whole file being fetched again. If the server doesn't honor the range (it
+    // answers `200 OK` instead of `206 Partial Content`), the download is restarted from the
+    // beginning of `w` so the file never ends up with resumed and fresh bytes mixed together.
+    //
+    // Compressed payloads are decompressed before they reach `w`, unless `no_decompress` is
+    // set: gzip is decoded as it streams, while a CAB payload is read into memory whole and its
+    // first file is then extracted. `resume_from` is ignored when the URL marks the payload as
+    // compressed (see `Decompressor::from_url`), since a partial compressed prefix can't be
+    // decompressed on its own; compression detected only from the response headers is not
+    // covered by this, and a resumed body of that kind fails to decode and returns `None`.
+    //
+    // When `checksum_algo` is given, the saved bytes are hashed as they're written and the
+    // digest returned in hex.
+    fn sdownload(&self,
+                 w: &mut (impl Write + Seek),
+                 resume_from: usize,
+                 no_decompress: bool,
+                 checksum_algo: Option<ChecksumAlgo>)
+                 -> Option<Option<String>> {
+        // Held until the response body has been fully read below.
+        let _permit = self.host_semaphore.as_deref().map(Semaphore::acquire);
+
+        // Resuming and checksumming don't mix: the hasher below is seeded fresh on every call,
+        // so a resumed download would only digest the newly-fetched tail rather than the whole
+        // file. Fetch from scratch whenever a checksum was requested so the digest covers every
+        // byte written to `w`.
+        let likely_compressed = !no_decompress && Decompressor::from_url(&self.url) != Decompressor::None;
+        let resume_from = if likely_compressed || checksum_algo.is_some() { 0 } else { resume_from };
+
+        let mut resp = with_retry(self.retries, self.retry_delay, || {
+                           let mut req = self.client.get(self.url.clone());
+                           if resume_from > 0 {
+                               req = req.header(header::RANGE, format!("bytes={}-", resume_from));
+                           }
+                           match req.send() {
+                               Ok(r) if r.status().is_success()
+                                        || r.status() == StatusCode::PARTIAL_CONTENT => Retry::Ok(r),
+                               Ok(r) if is_retryable_status(r.status()) => {
+                                   Retry::Retryable(retry_after_delay(r.headers()))
+                               }
+                               Ok(_) => Retry::Fatal,
+                               Err(_) => Retry::Retryable(None),
+                           }
+                       })?;
+
+        let mut network_length = if resume_from > 0 && resp.status() == StatusCode::PARTIAL_CONTENT {
+            w.seek(SeekFrom::Start(resume_from as u64)).ok()?;
+            resume_from
         } else {
-            None
+            w.seek(SeekFrom::Start(0)).ok()?;
+            0
+        };
+
+        let decompressor = if no_decompress {
+            Decompressor::None
+        } else {
+            Decompressor::detect(&self.url, resp.headers())
+        };
+
+        let mut hasher = checksum_algo.map(Hasher::new);
+
+        match decompressor {
+            Decompressor::None => {
+                let buffer = &mut vec![0u8; BUFFER_SIZE];
+                loop {
+                    let count = resp.read(buffer).ok()?;
+                    if count == 0 {
+                        break
+                    }
+                    w.write_all(&buffer[0..count]).ok()?;
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(&buffer[0..count]);
+                    }
+                    network_length += count;
+                }
+            }
+
+            Decompressor::Gzip => {
+                let network_bytes = AtomicUsize::new(network_length);
+                let mut decoder = GzDecoder::new(CountingReader { inner: &mut resp,
+                                                                  count: &network_bytes });
+                let buffer = &mut vec![0u8; BUFFER_SIZE];
+                loop {
+                    let count = decoder.read(buffer).ok()?;
+                    if count == 0 {
+                        break
+                    }
+                    w.write_all(&buffer[0..count]).ok()?;
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(&buffer[0..count]);
+                    }
+                }
+                network_length = network_bytes.load(Ordering::Relaxed);
+            }
+
+            Decompressor::Cab => {
+                let mut raw = Vec::with_capacity(self.length);
+                resp.read_to_end(&mut raw).ok()?;
+                network_length = raw.len();
+
+                let mut cabinet = Cabinet::new(Cursor::new(raw)).ok()?;
+                let name = cabinet.folder_entries()
+                                  .flat_map(|folder| folder.file_entries())
+                                  .next()?
+                                  .name()
+                                  .to_owned();
+                let mut file = cabinet.read_file(&name).ok()?;
+
+                let buffer = &mut vec![0u8; BUFFER_SIZE];
+                loop {
+                    let count = file.read(buffer).ok()?;
+                    if count == 0 {
+                        break
+                    }
+                    w.write_all(&buffer[0..count]).ok()?;
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(&buffer[0..count]);
+                    }
+                }
+            }
         }
+        assert_eq!(self.length, network_length);
+
+        Some(hasher.map(Hasher::finalize_hex))
     }
+}
+
+// Tracks bytes pulled from `inner`, independent of a decompressor wrapping it.
+struct CountingReader<'a, R> {
+    inner: R,
+    count: &'a AtomicUsize,
+}
+
+impl<'a, R: Read> Read for CountingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.count.fetch_add(count, Ordering::Relaxed);
+        Ok(count)
+    }
+}
 
-    fn sdownload(&self, w: &mut impl Write) -> Option<&Path> {
-        let resp = &mut self.client.get(self.url.clone()).send().ok()?;
+// What to decompress a downloaded payload with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decompressor {
+    None,
+    Gzip,
+    Cab,
+}
+
+impl Decompressor {
+    // e.g. `foo.pdb` -> `foo.pd_`, `foo.dll` -> `foo.dl_`.
+    fn from_url(url: &Url) -> Self {
+        match url.path().rsplit('.').next() {
+            Some(ext) if ext.ends_with('_') => Decompressor::Cab,
+            _ => Decompressor::None,
+        }
+    }
 
-        let buffer = &mut vec![0u8; BUFFER_SIZE];
-        let mut saved_length = 0usize;
-        loop {
-            let count = resp.read(buffer).ok()?;
-            if count == 0 {
-                break
+    fn detect(url: &Url, headers: &HeaderMap) -> Self {
+        if let Some(encoding) = headers.get(header::CONTENT_ENCODING).and_then(|v| v.to_str().ok()) {
+            if encoding.eq_ignore_ascii_case("gzip") {
+                return Decompressor::Gzip;
             }
-            w.write_all(&buffer[0..count]).ok()?;
-            saved_length += count;
         }
-        assert_eq!(self.length, saved_length);
+        if let Some(content_type) = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+            if content_type.eq_ignore_ascii_case("application/vnd.ms-cab-compressed") {
+                return Decompressor::Cab;
+            }
+        }
+        Decompressor::from_url(url)
+    }
+}
 
-        Some(self.name.as_path())
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChecksumAlgo {
+    Sha256,
+    Md5,
+}
+
+fn algo_str(algo: ChecksumAlgo) -> &'static str {
+    match algo {
+        ChecksumAlgo::Sha256 => "sha256",
+        ChecksumAlgo::Md5 => "md5",
+    }
+}
+
+// An expected `<algo>:<hex>` digest, e.g. `sha256:9f86d081...`.
+#[derive(Debug, Clone)]
+struct ExpectedChecksum {
+    algo: ChecksumAlgo,
+    hex: String,
+}
+
+impl ExpectedChecksum {
+    fn parse(spec: &str) -> Option<Self> {
+        let colon = spec.find(':')?;
+        let algo = match spec[..colon].to_ascii_lowercase().as_str() {
+            "sha256" => ChecksumAlgo::Sha256,
+            "md5" => ChecksumAlgo::Md5,
+            _ => return None,
+        };
+        Some(ExpectedChecksum { algo,
+                                hex: spec[colon + 1..].to_ascii_lowercase() })
+    }
+}
+
+// One line of the input list: `<uri>\t<algo>:<hex>`, the checksum part optional.
+struct Entry {
+    uri: String,
+    checksum: Option<ExpectedChecksum>,
+}
+
+impl Entry {
+    fn parse(line: &str) -> Self {
+        let mut parts = line.splitn(2, '\t');
+        let uri = parts.next().unwrap_or(line).to_owned();
+        let checksum = parts.next().and_then(ExpectedChecksum::parse);
+        Entry { uri, checksum }
+    }
+}
+
+// Wraps whichever hasher a file's expected checksum calls for.
+enum Hasher {
+    Sha256(Sha256),
+    Md5(Md5),
+}
+
+impl Hasher {
+    fn new(algo: ChecksumAlgo) -> Self {
+        match algo {
+            ChecksumAlgo::Sha256 => Hasher::Sha256(Sha256::new()),
+            ChecksumAlgo::Md5 => Hasher::Md5(Md5::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Md5(h) => h.update(data),
+        }
+    }
+
+    fn finalize_hex(self) -> String {
+        let digest: Vec<u8> = match self {
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Md5(h) => h.finalize().to_vec(),
+        };
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+// Path of the `.partial` file a download is staged under until it reaches its full length.
+fn partial_path(file_path: &Path) -> PathBuf {
+    let mut name = file_path.as_os_str().to_owned();
+    name.push(".partial");
+    PathBuf::from(name)
+}
+
+// Path of the sidecar file recording the validator (see `validator_of`) of the response that
+// started a `.partial` download, so a later resume can tell whether the server's content has
+// changed underneath it since.
+fn validator_marker_path(file_path: &Path) -> PathBuf {
+    let mut name = file_path.as_os_str().to_owned();
+    name.push(".validator");
+    PathBuf::from(name)
+}
+
+// Whether resuming from the bytes already on disk is safe to trust: true unless both the
+// current response's validator and the one recorded when the `.partial` download started are
+// known and disagree, which means the server served different content in between (a redeploy,
+// an inconsistent mirror, ...) and appending the new tail to the stale prefix would corrupt the
+// file. With no validator on either side to compare, we have no way to detect that and fall
+// back to trusting the length check, as before.
+fn resume_is_safe(current_validator: Option<&str>, validator_marker_path: &Path) -> bool {
+    let current = match current_validator {
+        Some(v) => v,
+        None => return true,
+    };
+    fs::read_to_string(validator_marker_path).map_or(true, |stored| stored == current)
+}
+
+// Records `validator` alongside a freshly (re)started `.partial` download so a later resume can
+// check it via `resume_is_safe`; clears any stale marker left over from a previous server
+// response that didn't provide one.
+fn record_validator(validator_marker_path: &Path, validator: Option<&str>) {
+    match validator {
+        Some(v) => {
+            fs::write(validator_marker_path, v).ok();
+        }
+        None => {
+            fs::remove_file(validator_marker_path).ok();
+        }
+    }
+}
+
+// Writes `buf` to `file` at `offset` without disturbing the file's shared cursor.
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    let mut written = 0;
+    while written < buf.len() {
+        written += file.seek_write(&buf[written..], offset + written as u64)?;
+    }
+    Ok(())
+}
+
+// Retries `attempt` once from scratch if it fails with a non-zero `resume_from`.
+fn download_with_resume(resume_from: usize, mut attempt: impl FnMut(usize) -> Option<()>) -> Option<()> {
+    if attempt(resume_from).is_some() {
+        return Some(());
     }
+    if resume_from > 0 { attempt(0) } else { None }
 }
 
-#[derive(AsStaticStr, EnumString, Debug, ToString, EnumIter)]
+#[derive(AsStaticStr, EnumString, Debug, Clone, Copy, ToString, EnumIter)]
 enum DownloadMode {
     #[strum(serialize = "seq")]
     Sequential,
@@ -189,6 +667,29 @@ struct Opt {
     #[structopt(short = "n", long = "threads", help = "number of threads [default: automatic]")]
     threads: Option<usize>,
 
+    #[structopt(long = "max-per-host",
+                help = "maximum number of concurrent requests to a single host [default: \
+                        unlimited]")]
+    max_per_host: Option<usize>,
+
+    #[structopt(long = "no-decompress",
+                help = "save compressed symbol payloads (CAB/gzip) as-is instead of \
+                        decompressing them")]
+    no_decompress: bool,
+
+    #[structopt(long = "retries",
+                help = "number of times to retry a request after a transient failure (timeout, \
+                        connection reset, 429/500/502/503/504)",
+                default_value = "3")]
+    retries: usize,
+
+    #[structopt(long = "retry-delay",
+                help = "base delay in milliseconds before the first retry; grows exponentially \
+                        (with jitter) for each subsequent one, unless the server sends a \
+                        Retry-After header",
+                default_value = "500")]
+    retry_delay_ms: u64,
+
     #[structopt(short = "m",
                 long = "mode",
                 help = "download mode",
@@ -201,10 +702,15 @@ struct Opt {
 fn main() -> Result<(), failure::Error> {
     let opt = Opt::from_args();
 
+    if opt.max_per_host == Some(0) {
+        return Err(failure::err_msg("--max-per-host must be at least 1"));
+    }
+
     let started = Instant::now();
 
     let uris: Vec<_> = BufReader::new(File::open(&opt.file)?).lines()
                                                              .map(|l| l.unwrap())
+                                                             .map(|line| Entry::parse(&line))
                                                              .collect();
 
     let pb = ProgressBar::new(uris.len() as u64);
@@ -216,6 +722,10 @@ fn main() -> Result<(), failure::Error> {
     let out_dir = &opt.out;
     let log_file = opt.log.as_path();
     let download_mode = opt.mode;
+    let max_per_host = opt.max_per_host;
+    let no_decompress = opt.no_decompress;
+    let retries = opt.retries;
+    let retry_delay = Duration::from_millis(opt.retry_delay_ms);
 
     if let Some(thread_num) = opt.threads {
         ThreadPoolBuilder::new().num_threads(thread_num)
@@ -223,8 +733,8 @@ fn main() -> Result<(), failure::Error> {
     }
 
     let ok_uris: Vec<_> = uris.par_iter()
-                              .map(|uri| -> Option<&str> {
-                                  let uri = uri.as_str();
+                              .map(|entry| -> Option<(&str, Option<String>)> {
+                                  let uri = entry.uri.as_str();
 
                                   let file_path = if let Some(outdir) = out_dir {
                                       let mut p = outdir.clone();
@@ -236,36 +746,133 @@ fn main() -> Result<(), failure::Error> {
 
                                   fs::create_dir_all(file_path.parent()?).ok()?;
 
-                                  let local_file =
-                                      &mut BufWriter::new(File::create(&file_path).ok()?);
-
                                   let url = format!("{}/{}", pdb_server, uri);
-                                  let remote_file = RemoteFile::from(&url)?;
-                                  match download_mode {
+                                  let remote_file =
+                                      RemoteFile::from(&url, max_per_host, retries, retry_delay)?;
+
+                                  let partial_path = partial_path(&file_path);
+                                  let validator_marker_path = validator_marker_path(&file_path);
+                                  let resume_from = match fs::metadata(&partial_path) {
+                                      Ok(meta) if (meta.len() as usize) < remote_file.length => {
+                                          meta.len() as usize
+                                      }
+                                      _ => 0,
+                                  };
+                                  // A same-length, different-content file served between the
+                                  // original attempt and this one (redeploy, inconsistent
+                                  // mirror, ...) would otherwise get its new tail appended to
+                                  // the stale prefix already on disk; don't trust resume_from
+                                  // unless the validators on both sides agree.
+                                  let resume_from =
+                                      if resume_is_safe(remote_file.validator.as_deref(),
+                                                        &validator_marker_path) {
+                                          resume_from
+                                      } else {
+                                          0
+                                      };
+
+                                  let mut digest: Option<String> = None;
+
+                                  // Ranges land on disk out of order across threads, so the
+                                  // ranged path has no way to hash the file in the same pass
+                                  // that writes it, or decompress it while it streams; route
+                                  // checksummed and compressed URIs through the sequential path
+                                  // instead of hashing/decompressing the finished file back from
+                                  // disk afterwards, which would double its disk I/O.
+                                  let likely_compressed = !no_decompress
+                                                           && Decompressor::from_url(&remote_file.url)
+                                                              != Decompressor::None;
+                                  let effective_mode = if entry.checksum.is_some() || likely_compressed
+                                  {
+                                      DownloadMode::Sequential
+                                  } else {
+                                      download_mode
+                                  };
+
+                                  // The server may not support resuming this particular file
+                                  // (e.g. it ignored our Range header); fall back to fetching it
+                                  // from scratch rather than leaving a corrupt partial download.
+                                  match effective_mode {
                                       DownloadMode::Concurrent => {
-                                          remote_file.rdownload(local_file)?;
+                                          download_with_resume(resume_from, |from| {
+                                              let file =
+                                                  OpenOptions::new().create(true)
+                                                                    .write(true)
+                                                                    .truncate(from == 0)
+                                                                    .open(&partial_path)
+                                                                    .ok()?;
+                                              if from == 0 {
+                                                  file.set_len(remote_file.length as u64).ok()?;
+                                                  record_validator(&validator_marker_path,
+                                                                   remote_file.validator.as_deref());
+                                              }
+                                              remote_file.rdownload(&file, from)?;
+                                              Some(())
+                                          })?;
+
+                                          fs::rename(&partial_path, &file_path).ok()?;
+                                          fs::remove_file(&validator_marker_path).ok();
                                       }
 
                                       DownloadMode::Sequential => {
-                                          remote_file.sdownload(local_file)?;
+                                          let algo = entry.checksum.as_ref().map(|c| c.algo);
+                                          download_with_resume(resume_from, |from| {
+                                              let mut file =
+                                                  BufWriter::new(OpenOptions::new().create(true)
+                                                                                  .write(true)
+                                                                                  .truncate(from
+                                                                                            == 0)
+                                                                                  .open(&partial_path)
+                                                                                  .ok()?);
+                                              if from == 0 {
+                                                  record_validator(&validator_marker_path,
+                                                                   remote_file.validator.as_deref());
+                                              }
+                                              digest = remote_file.sdownload(&mut file,
+                                                                             from,
+                                                                             no_decompress,
+                                                                             algo)?;
+                                              file.flush().ok()
+                                          })?;
+
+                                          fs::rename(&partial_path, &file_path).ok()?;
+                                          fs::remove_file(&validator_marker_path).ok();
                                       }
                                   }
-                                  //   remote_file.rdownload(local_file)?;
-                                  //   remote_file.sdownload(local_file)?;
+
+                                  let logged_digest = if let Some(expected) = entry.checksum.as_ref() {
+                                      let verified = digest.as_deref()
+                                                            .map_or(false,
+                                                                    |d| d.eq_ignore_ascii_case(&expected.hex));
+                                      if !verified {
+                                          fs::remove_file(&file_path).ok();
+                                          pb.println(format!("{}: checksum mismatch, file discarded",
+                                                              uri));
+                                          return None;
+                                      }
+                                      Some(format!("{}:{}", algo_str(expected.algo), expected.hex))
+                                  } else {
+                                      None
+                                  };
 
                                   pb.inc(1);
 
-                                  Some(uri)
+                                  Some((uri, logged_digest))
                               })
                               .collect();
     let ok_uris = ok_uris.iter()
-                         .filter(|v| v.is_some())
-                         .map(|v| v.unwrap())
+                         .filter_map(|v| v.as_ref())
                          .collect::<Vec<_>>();
 
     if !ok_uris.is_empty() {
         let log_file = &mut BufWriter::new(File::create(log_file)?);
-        writeln!(log_file, "{}", ok_uris.join("\n"))?;
+        let log_lines: Vec<_> = ok_uris.iter()
+                                       .map(|(uri, digest)| match digest {
+                                           Some(digest) => format!("{}\t{}", uri, digest),
+                                           None => uri.to_string(),
+                                       })
+                                       .collect();
+        writeln!(log_file, "{}", log_lines.join("\n"))?;
 
         pb.finish_and_clear();
 
@@ -280,3 +887,114 @@ fn main() -> Result<(), failure::Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expected_checksum_parses_sha256_and_md5() {
+        let c = ExpectedChecksum::parse("sha256:ABCDEF").unwrap();
+        assert_eq!(c.algo, ChecksumAlgo::Sha256);
+        assert_eq!(c.hex, "abcdef");
+
+        let c = ExpectedChecksum::parse("MD5:123456").unwrap();
+        assert_eq!(c.algo, ChecksumAlgo::Md5);
+        assert_eq!(c.hex, "123456");
+    }
+
+    #[test]
+    fn expected_checksum_rejects_unknown_algo_and_missing_colon() {
+        assert!(ExpectedChecksum::parse("crc32:12345678").is_none());
+        assert!(ExpectedChecksum::parse("sha256").is_none());
+    }
+
+    #[test]
+    fn decompressor_from_url_detects_the_trailing_underscore_convention() {
+        assert_eq!(Decompressor::from_url(&Url::parse("https://x/foo.pd_").unwrap()),
+                   Decompressor::Cab);
+        assert_eq!(Decompressor::from_url(&Url::parse("https://x/foo.dl_").unwrap()),
+                   Decompressor::Cab);
+        assert_eq!(Decompressor::from_url(&Url::parse("https://x/foo.pdb").unwrap()),
+                   Decompressor::None);
+    }
+
+    #[test]
+    fn decompressor_detect_prefers_headers_over_the_url() {
+        let url = Url::parse("https://x/foo.pdb").unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        assert_eq!(Decompressor::detect(&url, &headers), Decompressor::Gzip);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/vnd.ms-cab-compressed"));
+        assert_eq!(Decompressor::detect(&url, &headers), Decompressor::Cab);
+
+        let headers = HeaderMap::new();
+        assert_eq!(Decompressor::detect(&url, &headers), Decompressor::None);
+    }
+
+    #[test]
+    fn resume_is_safe_without_a_stored_marker() {
+        let marker = std::env::temp_dir().join("swget_test_resume_is_safe_no_marker.validator");
+        fs::remove_file(&marker).ok();
+
+        assert!(resume_is_safe(Some("etag:abc"), &marker));
+        assert!(resume_is_safe(None, &marker));
+    }
+
+    #[test]
+    fn resume_is_safe_agrees_or_disagrees_with_the_stored_marker() {
+        let marker = std::env::temp_dir().join("swget_test_resume_is_safe_marker.validator");
+        fs::write(&marker, "etag:abc").unwrap();
+
+        assert!(resume_is_safe(Some("etag:abc"), &marker));
+        assert!(!resume_is_safe(Some("etag:def"), &marker));
+        assert!(resume_is_safe(None, &marker));
+
+        fs::remove_file(&marker).ok();
+    }
+
+    #[test]
+    fn is_retryable_status_matches_429_and_5xx() {
+        for status in &[StatusCode::TOO_MANY_REQUESTS,
+                         StatusCode::INTERNAL_SERVER_ERROR,
+                         StatusCode::BAD_GATEWAY,
+                         StatusCode::SERVICE_UNAVAILABLE,
+                         StatusCode::GATEWAY_TIMEOUT] {
+            assert!(is_retryable_status(*status));
+        }
+        for status in &[StatusCode::OK, StatusCode::NOT_FOUND, StatusCode::FORBIDDEN] {
+            assert!(!is_retryable_status(*status));
+        }
+    }
+
+    #[test]
+    fn jittered_backoff_grows_with_attempt_and_adds_only_positive_jitter() {
+        let base = Duration::from_millis(100);
+        for attempt in 0..5 {
+            let backoff = jittered_backoff(base, attempt);
+            let floor = base * 2u32.pow(attempt as u32);
+            let ceil = floor + floor / 2;
+            assert!(backoff >= floor && backoff <= ceil,
+                    "attempt {}: {:?} not in [{:?}, {:?}]",
+                    attempt,
+                    backoff,
+                    floor,
+                    ceil);
+        }
+    }
+
+    #[test]
+    fn jittered_backoff_caps_the_exponent() {
+        let base = Duration::from_millis(1);
+        let at_cap = jittered_backoff(base, 10);
+        let past_cap = jittered_backoff(base, 50);
+        let floor = base * 2u32.pow(10);
+        assert!(at_cap >= floor);
+        assert!(past_cap >= floor);
+        assert!(past_cap <= floor + floor / 2);
+    }
+}